@@ -2,10 +2,10 @@ use std::fmt::{self, Write};
 use std::borrow::Cow;
 use std::collections::HashMap;
 use ast::{Content, Node};
-use pulldown_cmark::{Event, Tag};
+use pulldown_cmark::{Alignment, Event, Tag};
 
 pub trait IntoHtml<C> {
-    fn render(&mut self, ctx: &mut C, buf: &mut String);
+    fn render<W: Write>(&mut self, ctx: &mut C, buf: &mut W) -> fmt::Result;
 }
 
 enum TagType {
@@ -13,9 +13,25 @@ enum TagType {
     Closing,
 }
 
+/// Hook for syntax highlighting fenced code blocks. Given the language taken
+/// from the fence's info string and the block's raw code, returns the HTML
+/// to insert in place of the (otherwise escaped) code, or `None` to fall
+/// back to plain escaped text.
+pub trait Highlighter {
+    fn highlight(&self, lang: &str, code: &str) -> Option<String>;
+}
+
 struct Context<'a> {
     tag_type: Option<TagType>,
     footnote_indices: HashMap<Cow<'a, str>, usize>,
+    footnote_defs: HashMap<Cow<'a, str>, String>,
+    footnote_ref_counts: HashMap<Cow<'a, str>, usize>,
+    id_map: HashMap<String, usize>,
+    toc: Vec<(u32, String, String)>,
+    highlighter: Option<&'a dyn Highlighter>,
+    table_alignments: Vec<Alignment>,
+    table_cell_index: usize,
+    in_table_head: bool,
 }
 
 impl<'a> Context<'a> {
@@ -23,27 +39,162 @@ impl<'a> Context<'a> {
         Context {
             tag_type: None,
             footnote_indices: HashMap::new(),
+            footnote_defs: HashMap::new(),
+            footnote_ref_counts: HashMap::new(),
+            id_map: HashMap::new(),
+            toc: Vec::new(),
+            highlighter: None,
+            table_alignments: Vec::new(),
+            table_cell_index: 0,
+            in_table_head: false,
         }
     }
 
-    fn render_tag(&self, tag: &str, buf: &mut String) {
-        let tag_closer = match self.tag_type {
-           Some(TagType::Closing) => "/",
-            _ => "",
-        };
-        buf.push_str(&format!("<{}{}>", tag_closer, tag));
+    fn with_highlighter(highlighter: &'a dyn Highlighter) -> Context<'a> {
+        let mut context = Context::new();
+        context.highlighter = Some(highlighter);
+        context
+    }
+
+    // Slugifies `text` and disambiguates it against every id handed out so
+    // far, rustdoc's `IdMap`-style: the first occurrence of a slug keeps it
+    // bare, later collisions get `-1`, `-2`, ...
+    fn unique_id(&mut self, text: &str) -> String {
+        let slug = slugify(text);
+        let mut id = slug.clone();
+        while self.id_map.contains_key(&id) {
+            let count = self.id_map.get_mut(&slug).unwrap();
+            id = format!("{}-{}", slug, count);
+            *count += 1;
+        }
+        self.id_map.insert(id.clone(), 1);
+        id
+    }
+
+    // `inner` is the header's content already rendered (and thus already
+    // escaped) for display; the slug and TOC entry need the raw plain text
+    // instead, which `unescape_html` recovers after `strip_tags` drops the
+    // markup.
+    fn finish_header<W: Write>(&mut self, level: u32, inner: &str, buf: &mut W) -> fmt::Result {
+        let text = unescape_html(&strip_tags(inner));
+        let id = self.unique_id(&text);
+        self.toc.push((level, id.clone(), text));
+
+        write!(buf, "<h{} id=\"{}\">", level, id)?;
+        buf.write_str(inner)?;
+        write!(buf, "</h{}>\n", level)
+    }
+
+    fn render_tag<W: Write>(&self, tag: &str, buf: &mut W) -> fmt::Result {
+        match self.tag_type {
+            Some(TagType::Closing) => write!(buf, "</{}>", tag),
+            _ => write!(buf, "<{}>", tag),
+        }
+    }
+
+    fn render_list_tag<W: Write>(&self, start: Option<usize>, buf: &mut W) -> fmt::Result {
+        let tag = if start.is_some() { "ol" } else { "ul" };
+        match self.tag_type {
+            Some(TagType::Opening) => {
+                write!(buf, "<{}", tag)?;
+                if let Some(start) = start {
+                    if start != 1 {
+                        write!(buf, " start=\"{}\"", start)?;
+                    }
+                }
+                buf.write_char('>')
+            },
+            Some(TagType::Closing) => write!(buf, "</{}>", tag),
+            None => Ok(()),
+        }
+    }
+
+    fn render_link_tag<W: Write>(&self, dest: &str, title: &str, buf: &mut W) -> fmt::Result {
+        match self.tag_type {
+            Some(TagType::Opening) => {
+                buf.write_str("<a href=\"")?;
+                escape_html(buf, dest)?;
+                buf.write_char('"')?;
+                if !title.is_empty() {
+                    buf.write_str(" title=\"")?;
+                    escape_html(buf, title)?;
+                    buf.write_char('"')?;
+                }
+                buf.write_char('>')
+            },
+            Some(TagType::Closing) => buf.write_str("</a>"),
+            None => Ok(()),
+        }
     }
 
-    fn render_nested_tags(&self, tags: &[&str], buf: &mut String) {
+    // `th` inside the head row, `td` everywhere else, with a `text-align`
+    // style driven by the table's per-column alignment.
+    fn render_table_cell<W: Write>(&mut self, buf: &mut W) -> fmt::Result {
+        let tag = if self.in_table_head { "th" } else { "td" };
         match self.tag_type {
             Some(TagType::Opening) => {
-                tags.into_iter().for_each(|t| self.render_tag(t, buf));
+                let align = self.table_alignments
+                    .get(self.table_cell_index)
+                    .cloned()
+                    .unwrap_or(Alignment::None);
+
+                write!(buf, "<{}", tag)?;
+                match align {
+                    Alignment::Left => buf.write_str(" style=\"text-align: left\"")?,
+                    Alignment::Center => buf.write_str(" style=\"text-align: center\"")?,
+                    Alignment::Right => buf.write_str(" style=\"text-align: right\"")?,
+                    Alignment::None => (),
+                }
+                buf.write_char('>')
             },
             Some(TagType::Closing) => {
-                tags.into_iter().rev().for_each(|t| self.render_tag(t, buf));
+                write!(buf, "</{}>", tag)?;
+                self.table_cell_index += 1;
+                Ok(())
             },
-            None => (),
-        };
+            None => Ok(()),
+        }
+    }
+
+    // `code` has already passed through the normal (escaping) content render
+    // pipeline, so it's unescaped back to raw source first: the highlighter
+    // needs real source text, and the no-highlighter fallback must only be
+    // escaped once.
+    fn render_code_block<W: Write>(&mut self, info_string: &str, code: &str, buf: &mut W) -> fmt::Result {
+        let code = unescape_html(code);
+        let lang = info_string.split_whitespace().next();
+
+        buf.write_str("<pre><code")?;
+        if let Some(lang) = lang {
+            buf.write_str(" class=\"language-")?;
+            escape_html(buf, lang)?;
+            buf.write_char('"')?;
+        }
+        buf.write_char('>')?;
+
+        let highlighted = lang.and_then(|lang| {
+            self.highlighter.and_then(|h| h.highlight(lang, &code))
+        });
+        match highlighted {
+            Some(html) => buf.write_str(&html)?,
+            None => escape_html(buf, &code)?,
+        }
+
+        buf.write_str("</code></pre>\n")
+    }
+
+    fn render_image<W: Write>(&self, dest: &str, title: &str, alt: &str, buf: &mut W) -> fmt::Result {
+        buf.write_str("<img src=\"")?;
+        escape_html(buf, dest)?;
+        buf.write_str("\" alt=\"")?;
+        escape_html(buf, alt)?;
+        buf.write_char('"')?;
+        if !title.is_empty() {
+            buf.write_str(" title=\"")?;
+            escape_html(buf, title)?;
+            buf.write_char('"')?;
+        }
+        buf.write_str(" />\n")
     }
 
     fn get_footnote_index(&mut self, id: Cow<'a, str>) -> usize {
@@ -51,78 +202,194 @@ impl<'a> Context<'a> {
         *self.footnote_indices.entry(id).or_insert(num_footnotes)
     }
 
-    fn render_footnote_reference(&mut self, id: Cow<'a, str>, buf: &mut String) {
-        buf.push_str("<sup class=\"footnote-reference\"><a href=\"#");
-        // We unwrap here because the String writer implementation will never
-        // fail.
-        escape_html(buf, &id).unwrap();
-        buf.push_str("\">");
-        buf.push_str(&*format!("{}", self.get_footnote_index(id)));
-        buf.push_str("</a></sup>");
+    fn render_footnote_reference<W: Write>(&mut self, id: Cow<'a, str>, buf: &mut W) -> fmt::Result {
+        let index = self.get_footnote_index(id.clone());
+        let occurrence = {
+            let count = self.footnote_ref_counts.entry(id.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        buf.write_str("<sup class=\"footnote-reference\"><a id=\"fnref-")?;
+        escape_html(buf, &id)?;
+        buf.write_char('-')?;
+        write!(buf, "{}", occurrence)?;
+        buf.write_str("\" href=\"#fn-")?;
+        escape_html(buf, &id)?;
+        buf.write_str("\">")?;
+        write!(buf, "{}", index)?;
+        buf.write_str("</a></sup>")
     }
 
-    fn render_footnote_definition(&mut self, id: Cow<'a, str>, buf: &mut String) {
-        match self.tag_type {
-            Some(TagType::Opening) => {
-                buf.push_str(
-                    "<div class=\"footnote-definition\" id=\"",
-                );
-                // We unwrap here because the String writer implementation will never
-                // fail.
-                escape_html(buf, &*id).unwrap();
-                buf.push_str("\"><sup class=\"footnote-definition-label\">");
-                buf.push_str(&*format!("{}", self.get_footnote_index(id)));
-                buf.push_str("</sup>\n");
-            },
-            Some(TagType::Closing) => {
-                buf.push_str("</div>")
-            },
-            None => (),
+    // Renders the trailing `<ol class="footnotes">` section, ordered by each
+    // footnote's first *reference*. Definitions that are never referenced
+    // have no index to sort by, so they're simply dropped rather than
+    // guessed at.
+    fn render_footnotes<W: Write>(&mut self, buf: &mut W) -> fmt::Result {
+        if self.footnote_defs.is_empty() {
+            return Ok(());
+        }
+
+        let mut ordered: Vec<(Cow<'a, str>, usize)> = self.footnote_indices
+            .iter()
+            .map(|(id, &index)| (id.clone(), index))
+            .collect();
+        ordered.sort_by_key(|&(_, index)| index);
+
+        buf.write_str("<hr><ol class=\"footnotes\">\n")?;
+        for (id, _) in ordered {
+            let def = match self.footnote_defs.remove(&id) {
+                Some(def) => def,
+                None => continue,
+            };
+
+            buf.write_str("<li id=\"fn-")?;
+            escape_html(buf, &id)?;
+            buf.write_str("\">")?;
+            buf.write_str(&def)?;
+
+            let occurrences = *self.footnote_ref_counts.get(&id).unwrap_or(&0);
+            for k in 1..=occurrences {
+                buf.write_str(" <a href=\"#fnref-")?;
+                escape_html(buf, &id)?;
+                buf.write_char('-')?;
+                write!(buf, "{}", k)?;
+                buf.write_str("\" class=\"footnote-backref\">\u{21a9}</a>")?;
+            }
+            buf.write_str("</li>\n")?;
         }
+        buf.write_str("</ol>\n")
     }
 }
 
 impl<'a> IntoHtml<Context<'a>> for Tag<'a> {
-    fn render(&mut self, context: &mut Context<'a>, buf: &mut String) {
+    fn render<W: Write>(&mut self, context: &mut Context<'a>, buf: &mut W) -> fmt::Result {
         match *self {
             Tag::Paragraph => context.render_tag("p", buf),
-            Tag::Header(n) => context.render_tag(&format!("h{}", n), buf),
-            Tag::CodeBlock(ref _info_string) => context.render_nested_tags(&["pre", "code"], buf),
-            Tag::FootnoteDefinition(ref id) => context.render_footnote_definition(id.clone(), buf),
-            _ => (),
+            Tag::BlockQuote => context.render_tag("blockquote", buf),
+            Tag::Emphasis => context.render_tag("em", buf),
+            Tag::Strong => context.render_tag("strong", buf),
+            Tag::List(start) => context.render_list_tag(start, buf),
+            Tag::Item => context.render_tag("li", buf),
+            Tag::Link(ref dest, ref title) => context.render_link_tag(dest, title, buf),
+            Tag::Table(ref alignments) => {
+                if let Some(TagType::Opening) = context.tag_type {
+                    context.table_alignments = alignments.clone();
+                }
+                context.render_tag("table", buf)
+            },
+            Tag::TableHead => {
+                match context.tag_type {
+                    Some(TagType::Opening) => {
+                        context.in_table_head = true;
+                        context.table_cell_index = 0;
+                        context.render_tag("thead", buf)?;
+                        context.render_tag("tr", buf)
+                    },
+                    Some(TagType::Closing) => {
+                        context.render_tag("tr", buf)?;
+                        context.render_tag("thead", buf)?;
+                        context.in_table_head = false;
+                        Ok(())
+                    },
+                    None => Ok(()),
+                }
+            },
+            Tag::TableRow => {
+                if let Some(TagType::Opening) = context.tag_type {
+                    context.table_cell_index = 0;
+                }
+                context.render_tag("tr", buf)
+            },
+            Tag::TableCell => context.render_table_cell(buf),
+            _ => Ok(()),
         }
     }
 }
 
 impl<'a> IntoHtml<Context<'a>> for Event<'a> {
-    fn render(&mut self, context: &mut Context<'a>, buf: &mut String) {
+    fn render<W: Write>(&mut self, context: &mut Context<'a>, buf: &mut W) -> fmt::Result {
         match *self {
-            Event::Text(ref text) | Event::Html(ref text) | Event::InlineHtml(ref text) => buf.push_str(text),
+            Event::Text(ref text) => escape_html(buf, text),
+            Event::Html(ref text) | Event::InlineHtml(ref text) => buf.write_str(text),
             Event::FootnoteReference(ref id) => context.render_footnote_reference(id.clone(), buf),
+            Event::SoftBreak => buf.write_char('\n'),
+            Event::HardBreak => buf.write_str("<br />"),
+            Event::Rule => buf.write_str("<hr />"),
+            Event::TaskListMarker(checked) => {
+                buf.write_str("<input type=\"checkbox\" disabled")?;
+                if checked {
+                    buf.write_str(" checked")?;
+                }
+                buf.write_str(" />")
+            },
             Event::Start(_) | Event::End(_) => unreachable!(),
-            _ => panic!("AHHHHHHH!!!!!!!!!!"),
+            _ => Ok(()),
         }
     }
 }
 
 impl<'a> IntoHtml<Context<'a>> for Node<'a> {
-    fn render(&mut self, context: &mut Context<'a>, buf: &mut String) {
+    fn render<W: Write>(&mut self, context: &mut Context<'a>, buf: &mut W) -> fmt::Result {
         match *self {
             Node::Block(ref mut tag, ref mut content) => {
+                if let Tag::FootnoteDefinition(ref id) = *tag {
+                    // Footnote definitions aren't written inline; they're
+                    // buffered and rendered together in a trailing section by
+                    // `Context::render_footnotes`.
+                    let mut def_buf = String::new();
+                    content.render(context, &mut def_buf)?;
+                    context.footnote_defs.insert(id.clone(), def_buf);
+                    return Ok(());
+                }
+
+                if let Tag::Header(n) = *tag {
+                    // The heading's `id` is derived from its own text, so the
+                    // inner content has to be rendered up front before the
+                    // opening tag can be written.
+                    let mut inner = String::new();
+                    content.render(context, &mut inner)?;
+                    context.finish_header(n as u32, &inner, buf)?;
+                    return Ok(());
+                }
+
+                if let Tag::CodeBlock(ref info_string) = *tag {
+                    // The info string's language, if any, decides both the
+                    // `language-*` class and whether the highlighter is asked,
+                    // so the code body has to be collected up front rather
+                    // than streamed straight onto `buf`.
+                    let mut code = String::new();
+                    content.render(context, &mut code)?;
+                    context.render_code_block(info_string, &code, buf)?;
+                    return Ok(());
+                }
+
+                if let Tag::Image(ref dest, ref title) = *tag {
+                    // `<img>` is a void element and its alt text is an
+                    // attribute, not inner HTML, so the usual open/content/
+                    // close flow doesn't apply: render the children purely to
+                    // recover their text.
+                    let mut inner = String::new();
+                    content.render(context, &mut inner)?;
+                    let alt = unescape_html(&strip_tags(&inner));
+                    context.render_image(dest, title, &alt, buf)?;
+                    return Ok(());
+                }
+
                 context.tag_type = Some(TagType::Opening);
-                tag.render(context, buf);
+                tag.render(context, buf)?;
 
                 context.tag_type = None;
-                content.render(context, buf);
+                content.render(context, buf)?;
 
                 context.tag_type = Some(TagType::Closing);
-                tag.render(context, buf);
-                buf.push('\n');
+                tag.render(context, buf)?;
+                buf.write_char('\n')?;
                 context.tag_type = None;
+                Ok(())
             },
             Node::Item(ref mut event) => event.render(context, buf),
         }
-
     }
 }
 
@@ -130,27 +397,173 @@ impl<'a, I> IntoHtml<Context<'a>> for Content<'a, I>
 where
     I: Iterator<Item = Event<'a>>,
 {
-    fn render(&mut self, context: &mut Context<'a>, buf: &mut String) {
+    fn render<W: Write>(&mut self, context: &mut Context<'a>, buf: &mut W) -> fmt::Result {
         for mut node in self {
-            node.render(context, buf);
+            node.render(context, buf)?;
         }
+        Ok(())
     }
 }
 
-pub fn into_html<'a, I>(content: &mut Content<'a, I>, buf: &mut String)
+pub fn into_html<'a, I, W>(content: &mut Content<'a, I>, buf: &mut W) -> fmt::Result
 where
-    I: Iterator<Item = Event<'a>>
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
 {
     let mut context = Context::new();
-    content.render(&mut context, buf);
+    content.render(&mut context, buf)?;
+    context.render_footnotes(buf)
+}
+
+/// Like [`into_html`], but also returns a nested `<ul>` table of contents
+/// built from the headers encountered while rendering `content` into `buf`.
+pub fn into_html_with_toc<'a, I, W>(content: &mut Content<'a, I>, buf: &mut W) -> Result<String, fmt::Error>
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    let mut context = Context::new();
+    content.render(&mut context, buf)?;
+    context.render_footnotes(buf)?;
+    render_toc(&context.toc)
+}
+
+/// Like [`into_html`], but code blocks whose info string names a language
+/// are run through `highlighter`; blocks without a language, or for which it
+/// returns `None`, are still escaped plain text.
+pub fn into_html_with_highlighter<'a, I, W>(
+    content: &mut Content<'a, I>,
+    buf: &mut W,
+    highlighter: &'a dyn Highlighter,
+) -> fmt::Result
+where
+    I: Iterator<Item = Event<'a>>,
+    W: Write,
+{
+    let mut context = Context::with_highlighter(highlighter);
+    content.render(&mut context, buf)?;
+    context.render_footnotes(buf)
+}
+
+// Lowercases `text`, turns runs of whitespace into single `-`s, and drops
+// everything that isn't alphanumeric or a hyphen.
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    let mut last_was_dash = false;
+
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+            last_was_dash = false;
+        } else if (c.is_whitespace() || c == '-') && !last_was_dash && !slug.is_empty() {
+            slug.push('-');
+            last_was_dash = true;
+        }
+    }
+
+    if slug.ends_with('-') {
+        slug.pop();
+    }
+
+    slug
+}
+
+// Strips `<tag>`s from already-rendered HTML to recover its plain text, used
+// to derive a heading's slug from its rendered inner content.
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+
+    text
+}
+
+// Reverses `escape_html`'s substitutions, recovering the original text from
+// something that was rendered (and therefore already escaped) for display.
+// Used to get back the raw plain text needed for slugs, alt text, and TOC
+// entries, which are escaped separately by whoever writes them out.
+fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        let tail = &rest[amp..];
+
+        let (replacement, len) = if tail.starts_with("&amp;") {
+            ("&", 5)
+        } else if tail.starts_with("&lt;") {
+            ("<", 4)
+        } else if tail.starts_with("&gt;") {
+            (">", 4)
+        } else if tail.starts_with("&quot;") {
+            ("\"", 6)
+        } else if tail.starts_with("&#39;") {
+            ("'", 5)
+        } else {
+            ("&", 1)
+        };
+
+        unescaped.push_str(replacement);
+        rest = &tail[len..];
+    }
+    unescaped.push_str(rest);
+
+    unescaped
 }
 
-fn escape_html<W: Write>(buf: &mut W, html: &str) -> Result<(), fmt::Error> {
+// Turns a flat `(level, id, text)` outline into a nested `<ul>` table of
+// contents, opening and closing sub-lists as the level rises and falls.
+fn render_toc(entries: &[(u32, String, String)]) -> Result<String, fmt::Error> {
+    if entries.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut toc = String::new();
+    let mut stack: Vec<u32> = Vec::new();
+
+    for &(level, ref id, ref text) in entries {
+        while stack.last().map_or(false, |&top| top > level) {
+            toc.push_str("</li></ul>\n");
+            stack.pop();
+        }
+
+        if stack.last().map_or(true, |&top| top < level) {
+            toc.push_str("<ul>\n");
+            stack.push(level);
+        } else {
+            toc.push_str("</li>\n");
+        }
+
+        toc.push_str("<li><a href=\"#");
+        escape_html(&mut toc, id)?;
+        toc.push_str("\">");
+        escape_html(&mut toc, text)?;
+        toc.push_str("</a>");
+    }
+
+    toc.push_str("</li>\n");
+    for _ in &stack {
+        toc.push_str("</ul>\n");
+    }
+
+    Ok(toc)
+}
+
+fn escape_html<W: Write>(buf: &mut W, html: &str) -> fmt::Result {
     for c in html.as_bytes() {
         match *c {
             b'"' => buf.write_str("&quot;")?,
             b'&' => buf.write_str("&amp;")?,
-            b'\'' => buf.write_str("&#47;")?,
+            b'\'' => buf.write_str("&#39;")?,
             b'<' => buf.write_str("&lt;")?,
             b'>' => buf.write_str("&gt;")?,
             _ => buf.write_char(*c as char)?,
@@ -158,3 +571,286 @@ fn escape_html<W: Write>(buf: &mut W, html: &str) -> Result<(), fmt::Error> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_footnotes_orders_by_first_reference_and_drops_unreferenced() {
+        let mut context = Context::new();
+
+        // Referenced out of definition order: "b" is seen before "a".
+        context.get_footnote_index(Cow::Borrowed("b"));
+        context.get_footnote_index(Cow::Borrowed("a"));
+        context.footnote_ref_counts.insert(Cow::Borrowed("b"), 1);
+        context.footnote_ref_counts.insert(Cow::Borrowed("a"), 2);
+
+        context.footnote_defs.insert(Cow::Borrowed("a"), "<p>A</p>".to_string());
+        context.footnote_defs.insert(Cow::Borrowed("b"), "<p>B</p>".to_string());
+        // Defined but never referenced; must not appear and must not panic.
+        context.footnote_defs.insert(Cow::Borrowed("c"), "<p>C</p>".to_string());
+
+        let mut buf = String::new();
+        context.render_footnotes(&mut buf).unwrap();
+
+        let b_pos = buf.find("id=\"fn-b\"").unwrap();
+        let a_pos = buf.find("id=\"fn-a\"").unwrap();
+        assert!(b_pos < a_pos, "footnotes should be ordered by first reference, not definition order");
+        assert!(!buf.contains("fn-c"), "an unreferenced definition should be dropped");
+
+        // "a" was referenced twice, so it gets two back-links.
+        assert_eq!(buf.matches("#fnref-a-").count(), 2);
+        assert!(buf.contains("#fnref-a-1"));
+        assert!(buf.contains("#fnref-a-2"));
+        // "b" was referenced once, so it gets exactly one back-link.
+        assert_eq!(buf.matches("#fnref-b-").count(), 1);
+    }
+
+    #[test]
+    fn slugify_lowercases_and_collapses_non_alphanumerics() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(slugify("  Leading   and   trailing  "), "leading-and-trailing");
+        assert_eq!(slugify("already-hyphenated"), "already-hyphenated");
+        assert_eq!(slugify(""), "");
+    }
+
+    #[test]
+    fn unique_id_disambiguates_collisions() {
+        let mut context = Context::new();
+        assert_eq!(context.unique_id("Intro"), "intro");
+        assert_eq!(context.unique_id("Intro"), "intro-1");
+        assert_eq!(context.unique_id("Intro"), "intro-2");
+        assert_eq!(context.unique_id("Other"), "other");
+    }
+
+    #[test]
+    fn render_toc_nests_by_header_level() {
+        let entries = vec![
+            (1, "top".to_string(), "Top".to_string()),
+            (2, "child".to_string(), "Child".to_string()),
+            (1, "second".to_string(), "Second".to_string()),
+        ];
+
+        let toc = render_toc(&entries).unwrap();
+
+        assert!(toc.contains("<a href=\"#top\">Top</a>"));
+        assert!(toc.contains("<a href=\"#child\">Child</a>"));
+        assert!(toc.contains("<a href=\"#second\">Second</a>"));
+        // The deeper header opens a nested list that must close again before
+        // its sibling at the shallower level is emitted.
+        let child_ul = toc.find("<ul>\n<li><a href=\"#child\"").unwrap();
+        let close = toc.find("</li></ul>\n").unwrap();
+        let second_li = toc.find("<li><a href=\"#second\"").unwrap();
+        assert!(child_ul < close);
+        assert!(close < second_li);
+    }
+
+    #[test]
+    fn render_toc_empty_is_empty() {
+        assert_eq!(render_toc(&[]).unwrap(), "");
+    }
+
+    #[test]
+    fn escape_html_escapes_apostrophes_as_a_numeric_reference() {
+        let mut buf = String::new();
+        escape_html(&mut buf, "John's guide").unwrap();
+        assert_eq!(buf, "John&#39;s guide");
+    }
+
+    #[test]
+    fn unescape_html_reverses_escape_html() {
+        let raw = "<b>A & B</b> \"quoted\" it's";
+        let mut escaped = String::new();
+        escape_html(&mut escaped, raw).unwrap();
+        assert_eq!(unescape_html(&escaped), raw);
+    }
+
+    #[test]
+    fn render_code_block_escapes_the_rendered_code_exactly_once() {
+        let mut context = Context::new();
+        // `code` arrives already escaped, as it would coming out of
+        // `Content::render`'s `Event::Text` handling.
+        let mut escaped = String::new();
+        escape_html(&mut escaped, "<b>A & B</b>").unwrap();
+
+        let mut buf = String::new();
+        context.render_code_block("", &escaped, &mut buf).unwrap();
+
+        assert!(buf.contains("&lt;b&gt;A &amp; B&lt;/b&gt;"));
+        assert!(!buf.contains("&amp;lt;"), "code should not be double-escaped");
+    }
+
+    #[test]
+    fn render_image_escapes_alt_text_exactly_once() {
+        let context = Context::new();
+        let alt = "Fish & Chips";
+
+        let mut buf = String::new();
+        context.render_image("cat.png", "", alt, &mut buf).unwrap();
+
+        assert!(buf.contains("alt=\"Fish &amp; Chips\""));
+        assert!(!buf.contains("&amp;amp;"), "alt text should not be double-escaped");
+    }
+
+    #[test]
+    fn finish_header_stores_unescaped_text_for_the_toc() {
+        let mut context = Context::new();
+        // `inner` is what `Content::render` produces: already escaped for
+        // display, e.g. for a heading with literal text "Fish & Chips".
+        let mut inner = String::new();
+        escape_html(&mut inner, "Fish & Chips").unwrap();
+
+        let mut buf = String::new();
+        context.finish_header(2, &inner, &mut buf).unwrap();
+
+        // The rendered tag reuses the already-escaped `inner` as-is.
+        assert!(buf.contains("<h2 id=\"fish-chips\">Fish &amp; Chips</h2>"));
+
+        // But the TOC entry must hold the raw, unescaped text so that
+        // `render_toc` can escape it exactly once.
+        assert_eq!(context.toc, vec![(2, "fish-chips".to_string(), "Fish & Chips".to_string())]);
+
+        let toc = render_toc(&context.toc).unwrap();
+        assert!(toc.contains("Fish &amp; Chips"));
+        assert!(!toc.contains("&amp;amp;"), "TOC text should not be double-escaped");
+    }
+
+    struct UppercaseHighlighter;
+
+    impl Highlighter for UppercaseHighlighter {
+        fn highlight(&self, lang: &str, code: &str) -> Option<String> {
+            if lang == "rust" {
+                Some(code.to_uppercase())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn render_code_block_passes_raw_source_to_the_highlighter() {
+        let highlighter = UppercaseHighlighter;
+        let mut context = Context::with_highlighter(&highlighter);
+
+        let mut escaped = String::new();
+        escape_html(&mut escaped, "a < b && b > c").unwrap();
+
+        let mut buf = String::new();
+        context.render_code_block("rust", &escaped, &mut buf).unwrap();
+
+        // The highlighter must see the real source, `<` and `&&` included,
+        // not the HTML-escaped form it was rendered through.
+        assert!(buf.contains("A < B && B > C"));
+    }
+
+    #[test]
+    fn render_code_block_falls_back_to_escaped_text_for_unknown_languages() {
+        let highlighter = UppercaseHighlighter;
+        let mut context = Context::with_highlighter(&highlighter);
+
+        let mut escaped = String::new();
+        escape_html(&mut escaped, "<script>").unwrap();
+
+        let mut buf = String::new();
+        context.render_code_block("text", &escaped, &mut buf).unwrap();
+
+        assert!(buf.contains("class=\"language-text\""));
+        assert!(buf.contains("&lt;script&gt;"));
+    }
+
+    fn render_open_close<'a>(tag: &mut Tag<'a>, context: &mut Context<'a>) -> String {
+        let mut buf = String::new();
+        context.tag_type = Some(TagType::Opening);
+        tag.render(context, &mut buf).unwrap();
+        context.tag_type = Some(TagType::Closing);
+        tag.render(context, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn emphasis_and_strong_render_as_em_and_strong() {
+        let mut context = Context::new();
+        assert_eq!(render_open_close(&mut Tag::Emphasis, &mut context), "<em></em>");
+        assert_eq!(render_open_close(&mut Tag::Strong, &mut context), "<strong></strong>");
+    }
+
+    #[test]
+    fn unordered_list_has_no_start_attribute() {
+        let mut context = Context::new();
+        assert_eq!(render_open_close(&mut Tag::List(None), &mut context), "<ul></ul>");
+    }
+
+    #[test]
+    fn ordered_list_emits_start_only_when_not_one() {
+        let mut context = Context::new();
+        assert_eq!(render_open_close(&mut Tag::List(Some(1)), &mut context), "<ol></ol>");
+        assert_eq!(render_open_close(&mut Tag::List(Some(3)), &mut context), "<ol start=\"3\"></ol>");
+    }
+
+    #[test]
+    fn link_tag_escapes_href_and_includes_an_optional_title() {
+        let mut context = Context::new();
+        let mut tag = Tag::Link(Cow::Borrowed("/a&b"), Cow::Borrowed(""));
+        assert_eq!(render_open_close(&mut tag, &mut context), "<a href=\"/a&amp;b\"></a>");
+
+        let mut tag = Tag::Link(Cow::Borrowed("/x"), Cow::Borrowed("Say \"hi\""));
+        assert_eq!(
+            render_open_close(&mut tag, &mut context),
+            "<a href=\"/x\" title=\"Say &quot;hi&quot;\"></a>"
+        );
+    }
+
+    #[test]
+    fn table_cells_carry_their_column_alignment_and_advance_the_index() {
+        let mut context = Context::new();
+        context.table_alignments = vec![Alignment::Left, Alignment::Right];
+        context.in_table_head = true;
+
+        let mut buf = String::new();
+        context.tag_type = Some(TagType::Opening);
+        context.render_table_cell(&mut buf).unwrap();
+        context.tag_type = Some(TagType::Closing);
+        context.render_table_cell(&mut buf).unwrap();
+
+        context.tag_type = Some(TagType::Opening);
+        context.render_table_cell(&mut buf).unwrap();
+        context.tag_type = Some(TagType::Closing);
+        context.render_table_cell(&mut buf).unwrap();
+
+        assert_eq!(
+            buf,
+            "<th style=\"text-align: left\"></th><th style=\"text-align: right\"></th>"
+        );
+    }
+
+    #[test]
+    fn task_list_marker_renders_a_disabled_checkbox() {
+        let mut context = Context::new();
+
+        let mut buf = String::new();
+        Event::TaskListMarker(false).render(&mut context, &mut buf).unwrap();
+        assert_eq!(buf, "<input type=\"checkbox\" disabled />");
+
+        let mut buf = String::new();
+        Event::TaskListMarker(true).render(&mut context, &mut buf).unwrap();
+        assert_eq!(buf, "<input type=\"checkbox\" disabled checked />");
+    }
+
+    #[test]
+    fn soft_break_hard_break_and_rule_render_their_atomic_html() {
+        let mut context = Context::new();
+
+        let mut buf = String::new();
+        Event::SoftBreak.render(&mut context, &mut buf).unwrap();
+        assert_eq!(buf, "\n");
+
+        let mut buf = String::new();
+        Event::HardBreak.render(&mut context, &mut buf).unwrap();
+        assert_eq!(buf, "<br />");
+
+        let mut buf = String::new();
+        Event::Rule.render(&mut context, &mut buf).unwrap();
+        assert_eq!(buf, "<hr />");
+    }
+}